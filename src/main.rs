@@ -1,11 +1,11 @@
 ///    ___           __________________  ___________
 ///   / _/__  ____  / __/ ___/  _/ __/ |/ / ___/ __/
-///  / _/ _ \/ __/ _\ \/ /___/ // _//    / /__/ _/  
+///  / _/ _ \/ __/ _\ \/ /___/ // _//    / /__/ _/
 /// /_/ \___/_/   /___/\___/___/___/_/|_/\___/___/
 ///
 /// Author : Benjamin Blundell - me@benjamin.computer
 /// A small program that lets us view a directory of
-/// tiff or fits files. It performs flattening and 
+/// tiff or fits files. It performs flattening and
 /// scaling so that we can view floating point images
 /// in GTK which takes only RGB-8 images.
 ///
@@ -23,6 +23,8 @@ extern crate gdk_pixbuf;
 extern crate glib;
 extern crate tiff;
 extern crate fitrs;
+extern crate gdk;
+extern crate memmap;
 
 use gtk::prelude::*;
 use gio::prelude::*;
@@ -35,216 +37,568 @@ use std::fs;
 use std::fs::File;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
 use std::{cell::Cell, rc::Rc, cell::RefCell};
+use std::io::Cursor;
 use tiff::decoder::{Decoder, DecodingResult};
+use tiff::encoder::{TiffEncoder, colortype, compression};
 use tiff::ColorType;
 use std::process;
 use gtk::{Application, ApplicationWindow, Button, Label};
+use gdk::keys::constants as keys;
 use fitrs::{Fits, FitsData, FitsDataArray};
+use memmap::Mmap;
 
-// Holds our models and our GTK+ application
-pub struct Explorer {
-    app: gtk::Application,
-    image_paths : Vec<PathBuf>,
-    image_index : Cell<usize>, // use this so we can mutate it later
+// Default cap on how many planes of a tiff stack we'll sum before we
+// stop refining the average - trades accuracy for responsiveness on
+// huge microscopy stacks. Overridable from the command line.
+const DEFAULT_MAX_PLANES : usize = 64;
+
+// The number of bins used when building the histogram we use to
+// pick percentile cut points. 65536 gives us plenty of resolution
+// even for 16-bit source data.
+const HISTOGRAM_BINS : usize = 65536;
+
+// How the raw float buffer gets mapped down into [0, 255]. All three
+// modes clip to a percentile range first so a handful of hot pixels
+// don't wash out the rest of the image; Linear then maps that range
+// straight across, Log and Asinh stretch the faint end.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ScaleMode {
+    Linear,
+    Log,
+    Asinh,
 }
 
-// Open a fits image, returing a gtk::Image and the width and height
-fn get_image_fits(path : &Path ) -> (gtk::Image, usize, usize, f32, f32) {
-    let fits = Fits::open(path).expect("Failed to open fits.");
-    let mut img_buffer : Vec<Vec<f32>> = vec![];
-    let mut width : usize = 0;
-    let mut height : usize = 0;
+impl ScaleMode {
+    // Cycle round to the next mode - used when the user presses a key.
+    fn next(&self) -> ScaleMode {
+        match self {
+            ScaleMode::Linear => ScaleMode::Log,
+            ScaleMode::Log => ScaleMode::Asinh,
+            ScaleMode::Asinh => ScaleMode::Linear,
+        }
+    }
 
-    // Iterate over HDUs
-    for hdu in fits.iter() {
-        println!("{:?}", hdu.value("EXTNAME"));
-        //println!("{:?}", hdu.read_data());
+    fn name(&self) -> &'static str {
+        match self {
+            ScaleMode::Linear => "linear",
+            ScaleMode::Log => "log",
+            ScaleMode::Asinh => "asinh",
+        }
     }
+}
 
-    // Assume first hdu is the one we want. Won't be always
-    // Get HDU by ID
-    let hdu_0 = fits.get(0).unwrap();
+// Which false-colour lookup table to map normalised intensity through.
+// Grayscale keeps the old R=G=B behaviour; the others give faint
+// structure far more contrast than grayscale can.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Palette {
+    Grayscale,
+    Viridis,
+    Inferno,
+    Heat,
+}
 
-    match hdu_0.read_data() {
-        FitsData::FloatingPoint32(FitsDataArray { shape, data }) => {
-            width = shape[1];
-            height = shape[0];
+impl Palette {
+    // Cycle round to the next palette - used when the user presses a key.
+    fn next(&self) -> Palette {
+        match self {
+            Palette::Grayscale => Palette::Viridis,
+            Palette::Viridis => Palette::Inferno,
+            Palette::Inferno => Palette::Heat,
+            Palette::Heat => Palette::Grayscale,
+        }
+    }
 
-            for _y in 0..height {
-                let mut row  : Vec<f32> = vec![];
-                for _x in 0..width {
-                    row.push(0 as f32);
-                }
-                img_buffer.push(row);
-            }
+    fn name(&self) -> &'static str {
+        match self {
+            Palette::Grayscale => "grayscale",
+            Palette::Viridis => "viridis",
+            Palette::Inferno => "inferno",
+            Palette::Heat => "heat",
+        }
+    }
 
-            for y in 0..height as usize {
-                for x in 0..width as usize {
-                    img_buffer[y][x] = data[y * height + x] as f32;
-                }
-            }
+    // A handful of colour stops, linearly interpolated between in
+    // colormap() below - the same effect as a 256-entry lookup table
+    // without hand-typing one out entry by entry.
+    fn stops(&self) -> &'static [[u8; 3]] {
+        match self {
+            Palette::Grayscale => &GRAYSCALE_STOPS,
+            Palette::Viridis => &VIRIDIS_STOPS,
+            Palette::Inferno => &INFERNO_STOPS,
+            Palette::Heat => &HEAT_STOPS,
         }
-        _ => { /* ... */ }
     }
-    
-    // Final buffer that we use that is a little smaller - u8
-    // and not u16, but also RGB, just to make GTK happy.
-    let mut final_buffer : Vec<u8> = vec![];
-    for _y in 0..height {
-        for _x in 0..width {
-            // GTK insists we have RGB so we triple everything :/
-            for _ in 0..3 {
-                final_buffer.push(0 as u8);
-            }
+}
+
+const GRAYSCALE_STOPS : [[u8; 3]; 2] = [
+    [0, 0, 0], [255, 255, 255],
+];
+
+const VIRIDIS_STOPS : [[u8; 3]; 8] = [
+    [68, 1, 84], [71, 44, 122], [59, 81, 139], [44, 113, 142],
+    [33, 144, 141], [39, 173, 129], [92, 200, 99], [253, 231, 37],
+];
+
+const INFERNO_STOPS : [[u8; 3]; 8] = [
+    [0, 0, 4], [31, 12, 72], [85, 15, 109], [136, 34, 106],
+    [186, 54, 85], [227, 89, 51], [249, 140, 10], [252, 255, 164],
+];
+
+// Classic "heat"/jet style map - blue through to red.
+const HEAT_STOPS : [[u8; 3]; 5] = [
+    [0, 0, 143], [0, 255, 255], [0, 255, 0], [255, 255, 0], [128, 0, 0],
+];
+
+// Map a normalised intensity in [0, 1] through the given palette's
+// lookup table, linearly interpolating between the nearest two stops.
+fn colormap(t : f32, palette : Palette) -> [u8; 3] {
+    let t = t.max(0.0).min(1.0);
+    let stops = palette.stops();
+    let n = stops.len();
+
+    let scaled = t * (n - 1) as f32;
+    let mut i = scaled.floor() as usize;
+    if i >= n - 1 { i = n - 2; }
+    let frac = scaled - i as f32;
+
+    let a = stops[i];
+    let b = stops[i + 1];
+    [
+        (a[0] as f32 + (b[0] as f32 - a[0] as f32) * frac) as u8,
+        (a[1] as f32 + (b[1] as f32 - a[1] as f32) * frac) as u8,
+        (a[2] as f32 + (b[2] as f32 - a[2] as f32) * frac) as u8,
+    ]
+}
+
+// Build a histogram of img_buffer and walk its cumulative distribution
+// to find the pixel values that sit at low_pct / high_pct through the
+// data. Used to clip outliers (hot pixels, cosmic rays etc) before we
+// scale down to 8 bits.
+fn percentile_clip(img_buffer : &Vec<Vec<f32>>, width : usize, height : usize,
+    minp : f32, maxp : f32, low_pct : f32, high_pct : f32) -> (f32, f32) {
+    let range = maxp - minp;
+    if range <= 0.0 {
+        return (minp, maxp);
+    }
+
+    let mut hist = vec![0u32; HISTOGRAM_BINS];
+    let mut total : u32 = 0;
+    for y in 0..height {
+        for x in 0..width {
+            let v = img_buffer[y][x];
+            let mut b = ((v - minp) / range * (HISTOGRAM_BINS - 1) as f32) as usize;
+            if b >= HISTOGRAM_BINS { b = HISTOGRAM_BINS - 1; }
+            hist[b] += 1;
+            total += 1;
         }
     }
 
-    // Find min/max
-    let mut minp : f32 = 1e12; // we might end up overflowing!
-    let mut maxp : f32 = 0.0;
+    let lo_target = (total as f32 * (low_pct / 100.0)) as u32;
+    let hi_target = (total as f32 * (high_pct / 100.0)) as u32;
+
+    let mut running : u32 = 0;
+    let mut lo_bin : usize = 0;
+    let mut hi_bin : usize = HISTOGRAM_BINS - 1;
+    let mut found_lo = false;
+    for b in 0..HISTOGRAM_BINS {
+        running += hist[b];
+        if !found_lo && running >= lo_target {
+            lo_bin = b;
+            found_lo = true;
+        }
+        if running >= hi_target {
+            hi_bin = b;
+            break;
+        }
+    }
+
+    let lo = minp + (lo_bin as f32 / (HISTOGRAM_BINS - 1) as f32) * range;
+    let hi = minp + (hi_bin as f32 / (HISTOGRAM_BINS - 1) as f32) * range;
+    if hi > lo { (lo, hi) } else { (minp, maxp) }
+}
+
+// Flatten a raw float buffer down to a packed RGB-8 buffer, using the
+// requested scaling mode. This is called both when we first load an
+// image and again whenever the user cycles the scale mode, so it never
+// touches the file itself - just the buffer already in memory.
+fn flatten(img_buffer : &Vec<Vec<f32>>, width : usize, height : usize, mode : ScaleMode, palette : Palette) -> Vec<u8> {
+    let mut minp : f32 = 1e12;
+    let mut maxp : f32 = -1e12;
     for y in 0..height {
         for x in 0..width {
-            if (img_buffer[y][x] as f32) > maxp { maxp = img_buffer[y][x] as f32; }
-            if (img_buffer[y][x] as f32) < minp { minp = img_buffer[y][x] as f32; }
+            if img_buffer[y][x] > maxp { maxp = img_buffer[y][x]; }
+            if img_buffer[y][x] < minp { minp = img_buffer[y][x]; }
         }
     }
 
+    let (lo, hi) = percentile_clip(img_buffer, width, height, minp, maxp, 0.5, 99.5);
+    let range = if hi - lo > 0.0 { hi - lo } else { 1.0 };
+
+    // a and beta are both tunable - a controls how aggressive the log
+    // stretch is, beta is a small fraction of the clipped range for asinh.
+    let a : f32 = 1000.0;
+    let beta : f32 = range * 0.1;
+    let ln_1_plus_a = (1.0 + a).ln();
+    let asinh_hi = (range / beta).asinh();
+
+    let mut final_buffer : Vec<u8> = vec![0; width * height * 3];
     for y in 0..height {
-        for x in 0..width  {
-            let colour = (img_buffer[y][x] / maxp * 255.0) as u8;
-            let idx = (y * (height ) + x) * 3;
-            final_buffer[idx] = colour;
-            final_buffer[idx+1] = colour;
-            final_buffer[idx+2] = colour;
+        for x in 0..width {
+            let mut v = img_buffer[y][x];
+            if v < lo { v = lo; }
+            if v > hi { v = hi; }
+
+            let t = match mode {
+                ScaleMode::Linear => (v - lo) / range,
+                ScaleMode::Log => ((1.0 + a * (v - lo) / range).ln()) / ln_1_plus_a,
+                ScaleMode::Asinh => ((v - lo) / beta).asinh() / asinh_hi,
+            };
+            let t = t.max(0.0).min(1.0);
+            let colour = colormap(t, palette);
+
+            let idx = (y * width + x) * 3;
+            final_buffer[idx] = colour[0];
+            final_buffer[idx + 1] = colour[1];
+            final_buffer[idx + 2] = colour[2];
         }
-    } 
-   
-    let b = Bytes::from(&final_buffer);
+    }
+    final_buffer
+}
 
+// Wrap a packed RGB-8 buffer up as a gtk::Image, ready to drop into a box.
+fn make_image(final_buffer : &Vec<u8>, width : usize, height : usize) -> gtk::Image {
+    let b = Bytes::from(final_buffer);
     let pixybuf = Pixbuf::new_from_bytes(&b,
         Colorspace::Rgb,
-        false, 
+        false,
         8,
         width as i32,
         height as i32,
         (width * 3 * 1) as i32
     );
-
-    let image : gtk::Image = gtk::Image::new_from_pixbuf(Some(&pixybuf));
-    return (image, width, height, minp, maxp);
+    gtk::Image::new_from_pixbuf(Some(&pixybuf))
 }
 
-// Convert our model into a gtk::Image that we can present to
-// the screen.
-fn get_image_tiff(path : &Path) -> (gtk::Image, usize, usize, f32, f32) {
-    let img_file = File::open(path).expect("Cannot find test image!");
-    let mut decoder = Decoder::new(img_file).expect("Cannot create decoder");
-
-    let width : usize = decoder.dimensions().unwrap().0 as usize;
-    let height : usize = decoder.dimensions().unwrap().1 as usize;
+// Pull a header card out as an f32, falling back to a default when the
+// card is absent or isn't a number (fitrs gives us back a HeaderValue).
+fn header_as_f32(hdu : &fitrs::Hdu, key : &str, default : f32) -> f32 {
+    match hdu.value(key) {
+        Some(fitrs::HeaderValue::RealFloatingNumber(v)) => *v as f32,
+        Some(fitrs::HeaderValue::IntegerNumber(v)) => *v as f32,
+        _ => default,
+    }
+}
 
-    assert_eq!(decoder.colortype().unwrap(), ColorType::Gray(16));
-    let img_res = decoder.read_image().unwrap();
+// Name an HDU for display - EXTNAME if it has one, otherwise just its index.
+fn hdu_label(hdu : &fitrs::Hdu, index : usize) -> String {
+    match hdu.value("EXTNAME") {
+        Some(fitrs::HeaderValue::CharacterString(s)) => s.clone(),
+        _ => format!("HDU{}", index),
+    }
+}
 
-    // Our buffer - we sum all the image here and then scale
+// Reshape a row-major flat buffer of already-unwrapped raw pixel values
+// into our Vec<Vec<f32>> buffer, applying BZERO/BSCALE on the way
+// (physical = BZERO + BSCALE * raw). Pulled out of read_fits_hdu below so
+// the physical-value math can be unit tested without needing a real
+// fitrs::Hdu.
+fn apply_bzero_bscale(shape : [usize; 2], raw : &[f32], bzero : f32, bscale : f32) -> (usize, usize, Vec<Vec<f32>>) {
+    let width = shape[1];
+    let height = shape[0];
     let mut img_buffer : Vec<Vec<f32>> = vec![];
-    for _y in 0..height {
-        let mut row  : Vec<f32> = vec![];
-        for _x in 0..width {
-            row.push(0 as f32);
+    for y in 0..height {
+        let mut row : Vec<f32> = vec![];
+        for x in 0..width {
+            row.push(bzero + bscale * raw[y * width + x]);
         }
         img_buffer.push(row);
     }
-    
-    // Final buffer that we use that is a little smaller - u8
-    // and not u16, but also RGB, just to make GTK happy.
-    let mut final_buffer : Vec<u8> = vec![];
-    for _y in 0..height {
-        for _x in 0..width {
-            // GTK insists we have RGB so we triple everything :/
-            for _ in 0..3 {
-                final_buffer.push(0 as u8);
-            }
+    (width, height, img_buffer)
+}
+
+// Decode whichever FitsData variant an HDU holds into our internal
+// Vec<Vec<f32>> buffer, applying BZERO/BSCALE so the physical values
+// come out right (physical = BZERO + BSCALE * raw).
+fn read_fits_hdu(hdu : &fitrs::Hdu) -> (usize, usize, Vec<Vec<f32>>) {
+    let bzero = header_as_f32(hdu, "BZERO", 0.0);
+    let bscale = header_as_f32(hdu, "BSCALE", 1.0);
+
+    match hdu.read_data() {
+        FitsData::FloatingPoint32(FitsDataArray { shape, data }) => {
+            let raw : Vec<f32> = data.iter().map(|v| *v as f32).collect();
+            apply_bzero_bscale(shape, &raw, bzero, bscale)
+        },
+        FitsData::FloatingPoint64(FitsDataArray { shape, data }) => {
+            let raw : Vec<f32> = data.iter().map(|v| *v as f32).collect();
+            apply_bzero_bscale(shape, &raw, bzero, bscale)
+        },
+        // Integer data comes back as Option<T> - fitrs represents a
+        // missing pixel (the FITS BLANK convention) as None, which we
+        // treat as 0 before BZERO/BSCALE are applied.
+        FitsData::IntegersI32(FitsDataArray { shape, data }) => {
+            let raw : Vec<f32> = data.iter().map(|v| v.unwrap_or(0) as f32).collect();
+            apply_bzero_bscale(shape, &raw, bzero, bscale)
+        },
+        FitsData::IntegersU32(FitsDataArray { shape, data }) => {
+            let raw : Vec<f32> = data.iter().map(|v| v.unwrap_or(0) as f32).collect();
+            apply_bzero_bscale(shape, &raw, bzero, bscale)
+        },
+        // Characters (tables) and anything else isn't image data.
+        _ => (0, 0, vec![]),
+    }
+}
+
+// List every *image* HDU in a fits file as (actual HDU index, label) so
+// the Explorer can offer them up for selection. Table
+// extensions and header-only HDUs (NAXIS=0 primary HDUs are normal in
+// multi-extension fits files) don't decode to a FitsData variant
+// read_fits_hdu knows how to turn into a buffer, so they're skipped
+// rather than handed to flatten()/make_image() as a bogus 0x0 image.
+fn list_fits_hdus(path : &Path) -> Vec<(usize, String)> {
+    let fits = Fits::open(path).expect("Failed to open fits.");
+    let mut labels = vec![];
+    for (i, hdu) in fits.iter().enumerate() {
+        let (width, height, _) = read_fits_hdu(&hdu);
+        if width == 0 || height == 0 {
+            continue;
         }
+        labels.push((i, format!("{}: {} ({}x{})", i, hdu_label(&hdu, i), width, height)));
     }
-   
-    // Now we've decoded, lets update the img_buffer
-    if let DecodingResult::U16(img_res) = img_res {
-        let mut levels : usize = 0;
-        for y in 0..height {
-            for x in 0..width {
-                img_buffer[y][x] = img_res[y * (height) + x] as f32;
-            }
+    labels
+}
+
+// Everything the UI needs once a frame has been loaded and flattened -
+// bundled up so we're not passing ever-longer tuples around.
+pub struct LoadedImage {
+    image : gtk::Image,
+    width : usize,
+    height : usize,
+    minp : f32,
+    maxp : f32,
+    // Raw values, kept so we can re-flatten without reopening the file.
+    buffer : Vec<Vec<f32>>,
+    // (actual fits HDU index, display label) for every *image* HDU, in
+    // cycle order - not every raw HDU, see list_fits_hdus().
+    hdu_labels : Vec<(usize, String)>,
+    // The packed RGB-8 buffer exactly as rendered on screen - this is
+    // what gets written out when the user hits "Save".
+    final_buffer : Vec<u8>,
+    // Set for tiff stacks that still have planes left to average in -
+    // the Explorer polls this to keep refining the image in the
+    // background after the first plane is already on screen.
+    tiff_stack : Option<Rc<RefCell<TiffStack>>>,
+}
+
+// Everything about a loaded frame except the gtk::Image widget itself -
+// gtk types aren't Send, so this is the shape we pass back from the
+// background prefetch thread below. get_image_fits builds one of these
+// and then wraps it in a LoadedImage on the GTK thread; get_image_tiff
+// builds its LoadedImage directly, since it also hands back a TiffStack.
+pub struct PrefetchedFrame {
+    width : usize,
+    height : usize,
+    minp : f32,
+    maxp : f32,
+    buffer : Vec<Vec<f32>>,
+    hdu_labels : Vec<(usize, String)>,
+    final_buffer : Vec<u8>,
+}
+
+// Read, find min/max and flatten a fits HDU - the part of get_image_fits
+// that doesn't touch GTK, so it can also run on the prefetch thread.
+// `hdu_index` is a position in the *filtered* (image-only) hdu_labels
+// list, not a raw fits HDU index - it gets translated below.
+fn load_frame_fits(path : &Path, mode : ScaleMode, palette : Palette, hdu_index : usize) -> PrefetchedFrame {
+    let fits = Fits::open(path).expect("Failed to open fits.");
+    let hdu_labels = list_fits_hdus(path);
+
+    // Clamp to a valid position rather than panicking if the caller
+    // asks for one that no longer exists (e.g. after switching files).
+    let pos = if hdu_index < hdu_labels.len() { hdu_index } else { 0 };
+    let actual_index = hdu_labels.get(pos).map(|(i, _)| *i).unwrap_or(0);
+    let hdu = fits.get(actual_index).unwrap_or(fits.get(0).unwrap());
+    let (width, height, img_buffer) = read_fits_hdu(&hdu);
+
+    // Find min/max - these are reported in the UI, separate from the
+    // percentile-clipped lo/hi used for scaling.
+    let mut minp : f32 = 1e12; // we might end up overflowing!
+    let mut maxp : f32 = 0.0;
+    for y in 0..height {
+        for x in 0..width {
+            if (img_buffer[y][x] as f32) > maxp { maxp = img_buffer[y][x] as f32; }
+            if (img_buffer[y][x] as f32) < minp { minp = img_buffer[y][x] as f32; }
         }
+    }
 
-        while decoder.more_images() {
-            let next_res = decoder.next_image();
-            match next_res {
-                Ok(_res) => {   
-                    let img_next = decoder.read_image().unwrap();
-                    if let DecodingResult::U16(img_next) = img_next {
-                        levels += 1;
-                        for y in 0..height {
-                            for x in 0..width {
-                                img_buffer[y][x] += img_next[y * (height) + x] as f32;
-                            }
-                        } 
-                    }
-                },
-                Err(_) => {}
+    let final_buffer = flatten(&img_buffer, width, height, mode, palette);
+    PrefetchedFrame { width, height, minp, maxp, buffer : img_buffer, hdu_labels, final_buffer }
+}
+
+// Open a fits image, returing a gtk::Image and the width, height, min/max,
+// the raw float buffer (so we can re-flatten it later without reopening
+// the file) and the list of HDUs available in the file.
+fn get_image_fits(path : &Path, mode : ScaleMode, palette : Palette, hdu_index : usize) -> LoadedImage {
+    let frame = load_frame_fits(path, mode, palette, hdu_index);
+    let image = make_image(&frame.final_buffer, frame.width, frame.height);
+    LoadedImage {
+        image, width : frame.width, height : frame.height, minp : frame.minp, maxp : frame.maxp,
+        buffer : frame.buffer, hdu_labels : frame.hdu_labels, final_buffer : frame.final_buffer,
+        tiff_stack : None,
+    }
+}
+
+// A tiff stack opened for lazy, incremental decoding. The raw bytes are
+// memory mapped rather than copied into a heap Vec up front, and planes
+// are pulled in one at a time with advance() so the first plane can be
+// shown before the rest have even been read - handy for multi-gigabyte
+// microscopy stacks that would otherwise stall the UI while we sum
+// every plane before showing anything.
+pub struct TiffStack {
+    decoder : Decoder<Cursor<Mmap>>,
+    width : usize,
+    height : usize,
+    sum : Vec<Vec<f32>>,
+    planes : usize,
+    cap : usize,
+}
+
+impl TiffStack {
+    // Map the file and decode just the first plane, ready for an
+    // immediate render.
+    fn open(path : &Path, cap : usize) -> TiffStack {
+        let file = File::open(path).expect("Cannot find test image!");
+        let mmap = unsafe { Mmap::map(&file).expect("Cannot mmap tiff") };
+        let mut decoder = Decoder::new(Cursor::new(mmap)).expect("Cannot create decoder");
+
+        let width : usize = decoder.dimensions().unwrap().0 as usize;
+        let height : usize = decoder.dimensions().unwrap().1 as usize;
+        assert_eq!(decoder.colortype().unwrap(), ColorType::Gray(16));
+
+        let mut sum : Vec<Vec<f32>> = vec![];
+        for _y in 0..height {
+            let mut row : Vec<f32> = vec![];
+            for _x in 0..width {
+                row.push(0 as f32);
             }
+            sum.push(row);
         }
-        // We take an average rather than a total sum
-        for y in 0..height {
-            for x in 0..width {
-                img_buffer[y][x] = img_buffer[y][x] / (levels as f32);
+
+        let img_res = decoder.read_image().unwrap();
+        if let DecodingResult::U16(img_res) = img_res {
+            for y in 0..height {
+                for x in 0..width {
+                    sum[y][x] = img_res[y * width + x] as f32;
+                }
             }
+        } else {
+            panic!("Wrong data type");
         }
 
-        // Find min/max
-        let mut minp : f32 = 1e12; // we might end up overflowing!
-        let mut maxp : f32 = 0.0;
-        for y in 0..height {
-            for x in 0..width {
-                if (img_buffer[y][x] as f32) > maxp { maxp = img_buffer[y][x] as f32; }
-                if (img_buffer[y][x] as f32) < minp { minp = img_buffer[y][x] as f32; }
-            }
+        TiffStack { decoder, width, height, sum, planes : 1, cap }
+    }
+
+    // Pull in the next plane if there is one and we haven't hit the
+    // cap yet. Returns true if a plane was added.
+    fn advance(&mut self) -> bool {
+        if self.planes >= self.cap || !self.decoder.more_images() {
+            return false;
         }
+        match self.decoder.next_image() {
+            Ok(_) => {
+                let img_next = self.decoder.read_image().unwrap();
+                if let DecodingResult::U16(img_next) = img_next {
+                    for y in 0..self.height {
+                        for x in 0..self.width {
+                            self.sum[y][x] += img_next[y * self.width + x] as f32;
+                        }
+                    }
+                    self.planes += 1;
+                    true
+                } else {
+                    false
+                }
+            },
+            Err(_) => false,
+        }
+    }
 
-        for y in 0..height {
-            for x in 0..width {
-                let colour = (img_buffer[y][x] / maxp * 255.0) as u8;
-                let idx = (y * (height) + x) * 3;
-                final_buffer[idx] = colour;
-                final_buffer[idx+1] = colour;
-                final_buffer[idx+2] = colour;
+    // The running average over every plane summed so far.
+    fn average(&self) -> Vec<Vec<f32>> {
+        let mut out = self.sum.clone();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                out[y][x] = out[y][x] / (self.planes as f32);
             }
-        } 
+        }
+        out
+    }
+}
 
-        let b = Bytes::from(&final_buffer);
-        println!("Succesfully read {} which has {} levels.", path.display(), levels);
+// Decode a whole (possibly multi-plane) 16-bit grayscale tiff stack to
+// completion, averaging every plane into a single Vec<Vec<f32>>. Used
+// by the batch converter, which - unlike the viewer - wants the final
+// answer rather than a progressively refined one.
+fn read_tiff_stack(path : &Path) -> (usize, usize, Vec<Vec<f32>>) {
+    let mut stack = TiffStack::open(path, std::usize::MAX);
+    while stack.advance() {}
+    println!("Succesfully read {} which has {} levels.", path.display(), stack.planes);
+    (stack.width, stack.height, stack.average())
+}
+
+// One-shot read, average and flatten of a tiff stack's first `max_planes`
+// planes - the part of get_image_tiff that doesn't touch GTK, so it can
+// also run on the prefetch thread. Unlike get_image_tiff this doesn't
+// keep a TiffStack around, so a prefetched tiff frame doesn't continue
+// refining in the background once it's shown - a reasonable trade-off
+// for a frame the user hasn't actually navigated to yet.
+fn load_frame_tiff(path : &Path, mode : ScaleMode, palette : Palette, max_planes : usize) -> PrefetchedFrame {
+    let mut stack = TiffStack::open(path, max_planes);
+    while stack.advance() {}
+    let (width, height, img_buffer) = (stack.width, stack.height, stack.average());
+
+    // Find min/max
+    let mut minp : f32 = 1e12; // we might end up overflowing!
+    let mut maxp : f32 = 0.0;
+    for y in 0..height {
+        for x in 0..width {
+            if (img_buffer[y][x] as f32) > maxp { maxp = img_buffer[y][x] as f32; }
+            if (img_buffer[y][x] as f32) < minp { minp = img_buffer[y][x] as f32; }
+        }
+    }
 
-        // Convert down the tiff so we can see it.
-        
-        let pixybuf = Pixbuf::new_from_bytes(&b,
-            Colorspace::Rgb,
-            false, 
-            8,
-            width as i32,
-            height as i32,
-            (width * 3 * 1) as i32
-        );
+    let final_buffer = flatten(&img_buffer, width, height, mode, palette);
+    let hdu_labels = vec![(0, format!("0: image ({}x{})", width, height))];
+    PrefetchedFrame { width, height, minp, maxp, buffer : img_buffer, hdu_labels, final_buffer }
+}
 
-        let image : gtk::Image = gtk::Image::new_from_pixbuf(Some(&pixybuf));
-        return (image, width, height, minp, maxp);
+// Convert our model into a gtk::Image that we can present to the
+// screen. Only the first plane has been decoded at this point - the
+// Explorer keeps refining the average in the background via the
+// returned TiffStack.
+fn get_image_tiff(path : &Path, mode : ScaleMode, palette : Palette, max_planes : usize) -> LoadedImage {
+    let stack = Rc::new(RefCell::new(TiffStack::open(path, max_planes)));
+    let (width, height, img_buffer) = {
+        let s = stack.borrow();
+        (s.width, s.height, s.average())
+    };
 
-    } else {
-        panic!("Wrong data type");
+    // Find min/max
+    let mut minp : f32 = 1e12; // we might end up overflowing!
+    let mut maxp : f32 = 0.0;
+    for y in 0..height {
+        for x in 0..width {
+            if (img_buffer[y][x] as f32) > maxp { maxp = img_buffer[y][x] as f32; }
+            if (img_buffer[y][x] as f32) < minp { minp = img_buffer[y][x] as f32; }
+        }
     }
 
-    let image: gtk::Image = gtk::Image::new();
-    (image, width, height, 0.0, 0.0)
+    let final_buffer = flatten(&img_buffer, width, height, mode, palette);
+    let image = make_image(&final_buffer, width, height);
+    let hdu_labels = vec![(0, format!("0: image ({}x{})", width, height))];
+    LoadedImage { image, width, height, minp, maxp, buffer : img_buffer, hdu_labels, final_buffer, tiff_stack : Some(stack) }
 }
 
 // Basic naive buffer copying program.
@@ -259,23 +613,232 @@ pub fn copy_buffer(in_buff : &Vec<Vec<f32>>, out_buff : &mut Vec<Vec<f32>>,
 
 // Wrapper around the get_image_*  functions depending on the image extension.
 // TODO - this could be neater
-fn get_image(path : &Path) -> (gtk::Image, usize, usize, f32, f32) {
-    let dummy : gtk::Image = gtk::Image::new();
+fn get_image(path : &Path, mode : ScaleMode, palette : Palette, hdu_index : usize, max_planes : usize) -> LoadedImage {
     if path.extension().unwrap() == "fits" {
-        let (image, width, height, mini, maxi) = get_image_fits(path);
-        return (image, width, height, mini, maxi);
+        return get_image_fits(path, mode, palette, hdu_index);
     } else if path.extension().unwrap() == "tif" ||
         path.extension().unwrap() == "tiff" {
-        let (image, width, height, mini, maxi) = get_image_tiff(path);
-        return (image, width, height, mini, maxi);
+        return get_image_tiff(path, mode, palette, max_planes);
+    }
+    LoadedImage {
+        image : gtk::Image::new(),
+        width : 0, height : 0, minp : 0.0, maxp : 0.0,
+        buffer : vec![], hdu_labels : vec![], final_buffer : vec![], tiff_stack : None,
+    }
+}
+
+// Same dispatch as get_image(), but stopping short of building the
+// gtk::Image - this is what the background prefetch thread calls, since
+// gtk types can't cross threads.
+fn get_frame_data(path : &Path, mode : ScaleMode, palette : Palette, hdu_index : usize, max_planes : usize) -> PrefetchedFrame {
+    if path.extension().unwrap() == "fits" {
+        return load_frame_fits(path, mode, palette, hdu_index);
+    } else if path.extension().unwrap() == "tif" ||
+        path.extension().unwrap() == "tiff" {
+        return load_frame_tiff(path, mode, palette, max_planes);
+    }
+    PrefetchedFrame {
+        width : 0, height : 0, minp : 0.0, maxp : 0.0,
+        buffer : vec![], hdu_labels : vec![], final_buffer : vec![],
+    }
+}
+
+// Write the currently displayed frame out as a PNG next to the source
+// file, reusing the exact RGB-8 buffer that's on screen so the PNG
+// matches the render pixel for pixel.
+fn save_frame_png(path : &Path, final_buffer : &Vec<u8>, width : usize, height : usize) {
+    let out_path = path.with_extension("png");
+    image::save_buffer(&out_path, final_buffer, width as u32, height as u32, image::ColorType::Rgb8)
+        .expect("Failed to write PNG");
+    println!("Saved {}", out_path.display());
+}
+
+// Which tiff compressor to use when batch converting. All three are
+// provided by the tiff crate's encoder module.
+#[derive(Copy, Clone)]
+pub enum Compressor {
+    Deflate,
+    Lzw,
+    PackBits,
+}
+
+// Flatten and clamp a float buffer into the u16 range a gray16 tiff can
+// hold, split out from write_gray16_tiff so it can be exercised without
+// touching the filesystem.
+fn clamp_to_gray16(width : usize, height : usize, buffer : &Vec<Vec<f32>>) -> Vec<u16> {
+    let mut data : Vec<u16> = vec![0; width * height];
+    for y in 0..height {
+        for x in 0..width {
+            let mut v = buffer[y][x];
+            if v < 0.0 { v = 0.0; }
+            if v > 65535.0 { v = 65535.0; }
+            data[y * width + x] = v as u16;
+        }
+    }
+    data
+}
+
+// Turn a raw float buffer into a 16-bit grayscale tiff, preserving the
+// original dynamic range instead of squashing it down to 8-bit RGB like
+// the viewer does.
+fn write_gray16_tiff(path : &Path, width : usize, height : usize, buffer : &Vec<Vec<f32>>, compressor : Compressor) {
+    let data = clamp_to_gray16(width, height, buffer);
+
+    let out_file = File::create(path).expect("Cannot create output tiff");
+    let mut encoder = TiffEncoder::new(out_file).expect("Cannot create tiff encoder");
+    match compressor {
+        Compressor::Deflate => {
+            encoder.write_image_with_compression::<colortype::Gray16, _>(
+                width as u32, height as u32, compression::Deflate::default(), &data)
+                .expect("Failed to write tiff");
+        }
+        Compressor::Lzw => {
+            encoder.write_image_with_compression::<colortype::Gray16, _>(
+                width as u32, height as u32, compression::Lzw, &data)
+                .expect("Failed to write tiff");
+        }
+        Compressor::PackBits => {
+            encoder.write_image_with_compression::<colortype::Gray16, _>(
+                width as u32, height as u32, compression::Packbits, &data)
+                .expect("Failed to write tiff");
+        }
     }
-    (dummy, 0, 0, 0.0, 0.0)
+}
+
+// Convert a single fits file into a compressed 16-bit tiff, reusing the
+// same HDU-reading logic the viewer uses. Only the first image HDU is
+// taken - good enough for the common single-extension case.
+fn convert_fits_to_tiff(path : &Path, compressor : Compressor) {
+    let fits = Fits::open(path).expect("Failed to open fits.");
+    let index = list_fits_hdus(path).first().map(|(i, _)| *i).unwrap_or(0);
+    let hdu = fits.get(index).unwrap();
+    let (width, height, buffer) = read_fits_hdu(&hdu);
+
+    let out_path = path.with_extension("tiff");
+    write_gray16_tiff(&out_path, width, height, &buffer, compressor);
+    println!("Converted {} -> {}", path.display(), out_path.display());
+}
+
+// Collapse a multi-plane tiff stack down to the single averaged plane
+// get_image_tiff already computes, written out as a compressed tiff.
+fn convert_tiff_stack_to_tiff(path : &Path, compressor : Compressor) {
+    let (width, height, buffer) = read_tiff_stack(path);
+
+    let stem = path.file_stem().unwrap().to_str().unwrap();
+    let out_path = path.with_file_name(format!("{}_collapsed.tiff", stem));
+    write_gray16_tiff(&out_path, width, height, &buffer, compressor);
+    println!("Converted {} -> {}", path.display(), out_path.display());
+}
+
+// Headless batch conversion: walk the same directory main() scans for
+// the viewer, and write a compressed tiff for every fits/tiff file in it.
+fn run_convert(dir : &Path, compressor : Compressor) {
+    let paths = fs::read_dir(dir).unwrap();
+    for path in paths {
+        match path {
+            Ok(file) => {
+                let filename = file.file_name();
+                let tx = filename.to_str().unwrap();
+                let full_path = dir.join(tx);
+                if tx.ends_with(".fits") {
+                    convert_fits_to_tiff(&full_path, compressor);
+                } else if tx.ends_with(".tif") || tx.ends_with(".tiff") {
+                    convert_tiff_stack_to_tiff(&full_path, compressor);
+                }
+            },
+            Err(_) => {
+                println!("Error walking directory.");
+            }
+        }
+    }
+}
+
+// Keep pulling planes into a tiff stack in the background, refreshing
+// the image on screen each time the running average refines, until the
+// stack runs out of planes (or hits its cap). `generation` is the
+// Explorer's stack_generation at spawn time - if the user has navigated
+// to a different frame by the time a tick fires, stack_generation will
+// have moved on and this stops touching the (no longer displayed) UI.
+fn spawn_stack_refinement(app : Rc<Explorer>, ibox_arc : Arc<Mutex<gtk::Box>>, window : ApplicationWindow, stack : Rc<RefCell<TiffStack>>, generation : u64) {
+    glib::timeout_add_local(100, move || {
+        if app.stack_generation.get() != generation {
+            return glib::Continue(false);
+        }
+        if !stack.borrow_mut().advance() {
+            return glib::Continue(false);
+        }
+
+        let (width, height, buffer, planes) = {
+            let s = stack.borrow();
+            (s.width, s.height, s.average(), s.planes)
+        };
+        let final_buffer = flatten(&buffer, width, height, app.scale_mode.get(), app.palette.get());
+        let image = make_image(&final_buffer, width, height);
+
+        let ibox_ref = ibox_arc.lock().unwrap();
+        let children : Vec<gtk::Widget> = (*ibox_ref).get_children();
+        for i in 0..children.len() {
+            (*ibox_ref).remove(&children[i]);
+        }
+        let dbox = gtk::Box::new(gtk::Orientation::Vertical, 3);
+        let dimstr = format!("width/height: {}x{}", width, height);
+        let label = Label::new(Some(&dimstr));
+        dbox.add(&label);
+        let planestr = format!("planes summed so far: {}", planes);
+        let label2 = Label::new(Some(&planestr));
+        dbox.add(&label2);
+        (*ibox_ref).add(&image);
+        (*ibox_ref).add(&dbox);
+        window.show_all();
+
+        *app.current_buffer.borrow_mut() = Some((buffer, width, height));
+        *app.current_final.borrow_mut() = Some(final_buffer);
+        glib::Continue(true)
+    });
 }
 
 // Our Explorer struct/class implementation. Mostly just runs the GTK
 // and keeps a hold on our models.
+pub struct Explorer {
+    app: gtk::Application,
+    image_paths : Vec<PathBuf>,
+    image_index : Cell<usize>, // use this so we can mutate it later
+    scale_mode : Cell<ScaleMode>,
+    // Which false-colour palette the current image is rendered through.
+    palette : Cell<Palette>,
+    // The raw buffer of the image currently on screen, kept around so
+    // cycling the scale mode doesn't mean re-reading the file.
+    current_buffer : RefCell<Option<(Vec<Vec<f32>>, usize, usize)>>,
+    // Bumped every time the displayed frame changes (navigating to a
+    // different file, or to a different HDU of the same one). A running
+    // stack refinement timer captures the generation it was spawned
+    // under and stops itself once this no longer matches, so leaving an
+    // unfinished tiff stack doesn't keep clobbering whatever's on screen
+    // now with the old file's average.
+    stack_generation : Cell<u64>,
+    // Which HDU of the current fits file is on screen, and the labels
+    // for every image HDU available in it, so 'h' can step through them.
+    hdu_index : Cell<usize>,
+    hdu_labels : RefCell<Vec<(usize, String)>>,
+    // The packed RGB-8 buffer exactly as rendered on screen, so "Save"
+    // writes out precisely what's being shown.
+    current_final : RefCell<Option<Vec<u8>>>,
+    // Cap on how many planes of a tiff stack we sum before we stop
+    // refining the average in the background.
+    max_planes : Cell<usize>,
+    // Neighbouring frames decoded and flattened ahead of time by a
+    // background thread, so "Next" can build the Pixbuf
+    // straight away instead of blocking the GTK thread on file IO.
+    // Keyed by (path, scale mode, palette) it was flattened with, not
+    // just path - a prefetch thread spawned under an old mode/palette
+    // can still be decoding when the user cycles to a new one, and we
+    // don't want its stale-mode frame served up once it lands. Shared
+    // with the prefetch thread via Arc<Mutex<..>>.
+    prefetch_cache : Arc<Mutex<HashMap<(PathBuf, ScaleMode, Palette), PrefetchedFrame>>>,
+}
+
 impl Explorer {
-    pub fn new(image_paths : Vec<PathBuf>) -> Rc<Self> {
+    pub fn new(image_paths : Vec<PathBuf>, max_planes : usize) -> Rc<Self> {
         let app = Application::new(
             Some("com.github.gtk-rs.examples.basic"),
             Default::default(),
@@ -283,32 +846,72 @@ impl Explorer {
 
         let image_index : Cell<usize> = Cell::new(0);
 
-        // Base buffer
-        let height : usize = 128;
-        let width : usize = 128;
-        let mut tbuf : Vec<Vec<f32>> = vec![];
-        for _y in 0..height {
-            let mut row  : Vec<f32> = vec![];
-            for _x in 0..width {
-                row.push(0 as f32);
-            }
-            tbuf.push(row);
-        }
-
         let explorer = Rc::new(Self {
             app,
             image_paths,
             image_index,
+            scale_mode : Cell::new(ScaleMode::Linear),
+            palette : Cell::new(Palette::Grayscale),
+            current_buffer : RefCell::new(None),
+            stack_generation : Cell::new(0),
+            hdu_index : Cell::new(0),
+            hdu_labels : RefCell::new(vec![]),
+            current_final : RefCell::new(None),
+            max_planes : Cell::new(max_planes),
+            prefetch_cache : Arc::new(Mutex::new(HashMap::new())),
         });
 
         explorer
     }
 
+    // Kick off a background thread that decodes and flattens the
+    // image(s) either side of `index` into prefetch_cache, using a
+    // scoped_threadpool::Pool so neighbours decode in parallel. Called
+    // after every navigation so the cache is always warm for next time.
+    fn prefetch_neighbours(app : Rc<Explorer>, index : usize) {
+        let mode = app.scale_mode.get();
+        let palette = app.palette.get();
+        let max_planes = app.max_planes.get();
+
+        let mut targets = vec![];
+        if index + 1 < app.image_paths.len() { targets.push((app.image_paths[index + 1].clone(), mode, palette)); }
+        if index > 0 { targets.push((app.image_paths[index - 1].clone(), mode, palette)); }
+        if targets.is_empty() { return; }
+
+        let cache = app.prefetch_cache.clone();
+
+        std::thread::spawn(move || {
+            let to_fetch : Vec<(PathBuf, ScaleMode, Palette)> = {
+                let cached = cache.lock().unwrap();
+                targets.into_iter().filter(|k| !cached.contains_key(k)).collect()
+            };
+            if to_fetch.is_empty() { return; }
+
+            let found = Arc::new(Mutex::new(vec![]));
+            let mut pool = scoped_threadpool::Pool::new(to_fetch.len() as u32);
+            pool.scoped(|scope| {
+                for key in &to_fetch {
+                    let (path, mode, palette) = key.clone();
+                    let found = found.clone();
+                    scope.execute(move || {
+                        let frame = get_frame_data(&path, mode, palette, 0, max_planes);
+                        found.lock().unwrap().push(((path, mode, palette), frame));
+                    });
+                }
+            });
+
+            let mut cached = cache.lock().unwrap();
+            for (key, frame) in Arc::try_unwrap(found).unwrap().into_inner().unwrap() {
+                cached.insert(key, frame);
+            }
+        });
+    }
+
     // Meat of the program
     pub fn run(&self, app: Rc<Self>) {
         let app = app.clone();
         let _args: Vec<String> = env::args().collect();
- 
+
         self.app.connect_activate( move |gtkapp| {
             let window = ApplicationWindow::new(gtkapp);
             let mut title: String = "FEX: ".to_owned();
@@ -320,22 +923,41 @@ impl Explorer {
             let dbox = gtk::Box::new(gtk::Orientation::Vertical, 3);
             let ibox = gtk::Box::new(gtk::Orientation::Horizontal, 2);
             let hbox = gtk::Box::new(gtk::Orientation::Horizontal, 3);
-            let (image, width, height, mini, maxi) = get_image(&(app.image_paths[0]));
-            ibox.add(&image);
-            let dimstr = format!("width/height: {}x{}", width, height); 
+            app.hdu_index.set(0);
+            let loaded = get_image(&(app.image_paths[0]), app.scale_mode.get(), app.palette.get(), app.hdu_index.get(), app.max_planes.get());
+            ibox.add(&loaded.image);
+            let dimstr = format!("width/height: {}x{}", loaded.width, loaded.height);
             let label = Label::new(Some(&dimstr));
             dbox.add(&label);
-            let dimstr = format!("min/max: {}x{}", mini, maxi); 
+            let dimstr = format!("min/max: {}x{}", loaded.minp, loaded.maxp);
             let label2 = Label::new(Some(&dimstr));
             dbox.add(&label2);
+            let modestr = format!("scale: {} (press 'm' to cycle)", app.scale_mode.get().name());
+            let label3 = Label::new(Some(&modestr));
+            dbox.add(&label3);
+            let hdustr = format!("{} (press 'h' to cycle)",
+                loaded.hdu_labels.get(app.hdu_index.get()).map(|(_, s)| s.clone()).unwrap_or_default());
+            let label4 = Label::new(Some(&hdustr));
+            dbox.add(&label4);
+            let palettestr = format!("palette: {} (press 'c' to cycle)", app.palette.get().name());
+            let label5 = Label::new(Some(&palettestr));
+            dbox.add(&label5);
             ibox.add(&dbox);
             vbox.add(&ibox);
             vbox.add(&hbox);
             window.add(&vbox);
+            *app.current_buffer.borrow_mut() = Some((loaded.buffer, loaded.width, loaded.height));
+            *app.hdu_labels.borrow_mut() = loaded.hdu_labels;
+            *app.current_final.borrow_mut() = Some(loaded.final_buffer);
+            Explorer::prefetch_neighbours(app.clone(), app.image_index.get());
 
             // Now look at buttons
             let button_accept = Button::new_with_label("Next");
+            let button_save = Button::new_with_label("Save");
             let ibox_arc = Arc::new(Mutex::new(ibox));
+            if let Some(stack) = loaded.tiff_stack {
+                spawn_stack_refinement(app.clone(), ibox_arc.clone(), window.clone(), stack, app.stack_generation.get());
+            }
             let ibox_accept = ibox_arc.clone();
             let app_accept = app.clone();
             let win_accept = window.clone();
@@ -350,32 +972,206 @@ impl Explorer {
                 } else {
                     app_accept.image_index.set(mi + 1);
                 }
-            
-                // Now move on to the next image
+
+                // Now move on to the next image. Bump the generation so
+                // any refinement timer still running for the image we're
+                // leaving notices and stops on its next tick.
+                app_accept.hdu_index.set(0);
+                app_accept.stack_generation.set(app_accept.stack_generation.get() + 1);
                 let ibox_ref = ibox_accept.lock().unwrap();
                 let children : Vec<gtk::Widget> = (*ibox_ref).get_children();
-                let (image, width, height, mini, maxi) = get_image(&(app_accept.image_paths[mi + 1]));
+
+                // If the background prefetch thread already decoded and
+                // flattened this frame, reuse it instead of blocking the
+                // GTK thread on file IO - this is the whole point of
+                // prefetch_neighbours().
+                let next_path = app_accept.image_paths[app_accept.image_index.get()].clone();
+                let cache_key = (next_path.clone(), app_accept.scale_mode.get(), app_accept.palette.get());
+                let prefetched = app_accept.prefetch_cache.lock().unwrap().remove(&cache_key);
+                let loaded = match prefetched {
+                    Some(frame) => {
+                        let image = make_image(&frame.final_buffer, frame.width, frame.height);
+                        LoadedImage {
+                            image, width : frame.width, height : frame.height,
+                            minp : frame.minp, maxp : frame.maxp, buffer : frame.buffer,
+                            hdu_labels : frame.hdu_labels, final_buffer : frame.final_buffer,
+                            tiff_stack : None,
+                        }
+                    },
+                    None => get_image(&next_path, app_accept.scale_mode.get(), app_accept.palette.get(), app_accept.hdu_index.get(), app_accept.max_planes.get()),
+                };
                 for i in 0..children.len() {
                     (*ibox_ref).remove(&children[i]);
                 }
 
                 let dbox = gtk::Box::new(gtk::Orientation::Vertical, 3);
-                let dimstr = format!("width/height: {}x{}", width, height); 
+                let dimstr = format!("width/height: {}x{}", loaded.width, loaded.height);
                 let label = Label::new(Some(&dimstr));
                 dbox.add(&label);
-                let dimstr = format!("min/max: {}x{}", mini, maxi); 
+                let dimstr = format!("min/max: {}x{}", loaded.minp, loaded.maxp);
                 let label2 = Label::new(Some(&dimstr));
                 dbox.add(&label2);
-                (*ibox_ref).add(&image);
+                let modestr = format!("scale: {} (press 'm' to cycle)", app_accept.scale_mode.get().name());
+                let label3 = Label::new(Some(&modestr));
+                dbox.add(&label3);
+                let hdustr = format!("{} (press 'h' to cycle)",
+                    loaded.hdu_labels.get(app_accept.hdu_index.get()).map(|(_, s)| s.clone()).unwrap_or_default());
+                let label4 = Label::new(Some(&hdustr));
+                dbox.add(&label4);
+                let palettestr = format!("palette: {} (press 'c' to cycle)", app_accept.palette.get().name());
+                let label5 = Label::new(Some(&palettestr));
+                dbox.add(&label5);
+                (*ibox_ref).add(&loaded.image);
                 (*ibox_ref).add(&dbox);
                 let mut title: String = "FEX: ".to_owned();
                 let opath: String = app_accept.image_paths[0].to_str().unwrap().to_string();
                 title.push_str(&opath);
                 win_accept.set_title(&title);
                 win_accept.show_all();
+                *app_accept.current_buffer.borrow_mut() = Some((loaded.buffer, loaded.width, loaded.height));
+                *app_accept.hdu_labels.borrow_mut() = loaded.hdu_labels;
+                *app_accept.current_final.borrow_mut() = Some(loaded.final_buffer);
+                if let Some(stack) = loaded.tiff_stack {
+                    spawn_stack_refinement(app_accept.clone(), ibox_accept.clone(), win_accept.clone(), stack, app_accept.stack_generation.get());
+                }
+                Explorer::prefetch_neighbours(app_accept.clone(), app_accept.image_index.get());
             });
 
             hbox.add(&button_accept);
+
+            // Save button - writes out the frame currently on screen.
+            let app_save = app.clone();
+            button_save.connect_clicked( move |_button| {
+                let mi = app_save.image_index.get();
+                let path = &app_save.image_paths[mi];
+                let cached = app_save.current_buffer.borrow();
+                let final_buffer = app_save.current_final.borrow();
+                if let (Some((_, width, height)), Some(final_buffer)) = (&*cached, &*final_buffer) {
+                    save_frame_png(path, final_buffer, *width, *height);
+                }
+            });
+
+            hbox.add(&button_save);
+
+            // Pressing 'm' cycles the scale mode and re-flattens the buffer
+            // we already have in memory, no re-reading the file. Pressing
+            // 'h' steps to the next HDU of the current fits file, which
+            // does need a re-read since it's genuinely different data.
+            let ibox_mode = ibox_arc.clone();
+            let app_mode = app.clone();
+            window.connect_key_press_event( move |win, event| {
+                if event.get_keyval() == keys::m {
+                    app_mode.scale_mode.set(app_mode.scale_mode.get().next());
+                    // Entries are keyed by (path, mode, palette), so a
+                    // prefetch thread still running under the old mode
+                    // can't hand back a stale-mode frame - this clear is
+                    // just to stop the now-unreachable old-mode entries
+                    // sitting around in memory.
+                    app_mode.prefetch_cache.lock().unwrap().clear();
+                    let buffer_dims = {
+                        let cached = app_mode.current_buffer.borrow();
+                        cached.as_ref().map(|(buffer, width, height)| (buffer.clone(), *width, *height))
+                    };
+                    if let Some((buffer, width, height)) = buffer_dims {
+                        let final_buffer = flatten(&buffer, width, height, app_mode.scale_mode.get(), app_mode.palette.get());
+                        let image = make_image(&final_buffer, width, height);
+
+                        let ibox_ref = ibox_mode.lock().unwrap();
+                        let children : Vec<gtk::Widget> = (*ibox_ref).get_children();
+                        for i in 0..children.len() {
+                            (*ibox_ref).remove(&children[i]);
+                        }
+                        let dbox = gtk::Box::new(gtk::Orientation::Vertical, 3);
+                        let dimstr = format!("width/height: {}x{}", width, height);
+                        let label = Label::new(Some(&dimstr));
+                        dbox.add(&label);
+                        let modestr = format!("scale: {} (press 'm' to cycle)", app_mode.scale_mode.get().name());
+                        let label3 = Label::new(Some(&modestr));
+                        dbox.add(&label3);
+                        (*ibox_ref).add(&image);
+                        (*ibox_ref).add(&dbox);
+                        win.show_all();
+
+                        *app_mode.current_final.borrow_mut() = Some(final_buffer);
+                    }
+                } else if event.get_keyval() == keys::c {
+                    app_mode.palette.set(app_mode.palette.get().next());
+                    // Entries are keyed by (path, mode, palette), so a
+                    // prefetch thread still running under the old
+                    // palette can't hand back a stale-palette frame -
+                    // this clear just stops the now-unreachable old
+                    // entries sitting around in memory.
+                    app_mode.prefetch_cache.lock().unwrap().clear();
+                    let buffer_dims = {
+                        let cached = app_mode.current_buffer.borrow();
+                        cached.as_ref().map(|(buffer, width, height)| (buffer.clone(), *width, *height))
+                    };
+                    if let Some((buffer, width, height)) = buffer_dims {
+                        let final_buffer = flatten(&buffer, width, height, app_mode.scale_mode.get(), app_mode.palette.get());
+                        let image = make_image(&final_buffer, width, height);
+
+                        let ibox_ref = ibox_mode.lock().unwrap();
+                        let children : Vec<gtk::Widget> = (*ibox_ref).get_children();
+                        for i in 0..children.len() {
+                            (*ibox_ref).remove(&children[i]);
+                        }
+                        let dbox = gtk::Box::new(gtk::Orientation::Vertical, 3);
+                        let dimstr = format!("width/height: {}x{}", width, height);
+                        let label = Label::new(Some(&dimstr));
+                        dbox.add(&label);
+                        let palettestr = format!("palette: {} (press 'c' to cycle)", app_mode.palette.get().name());
+                        let label3 = Label::new(Some(&palettestr));
+                        dbox.add(&label3);
+                        (*ibox_ref).add(&image);
+                        (*ibox_ref).add(&dbox);
+                        win.show_all();
+
+                        *app_mode.current_final.borrow_mut() = Some(final_buffer);
+                    }
+                } else if event.get_keyval() == keys::h {
+                    let hdu_count = app_mode.hdu_labels.borrow().len();
+                    if hdu_count > 1 {
+                        let next = (app_mode.hdu_index.get() + 1) % hdu_count;
+                        app_mode.hdu_index.set(next);
+                        app_mode.stack_generation.set(app_mode.stack_generation.get() + 1);
+                        let path = app_mode.image_paths[app_mode.image_index.get()].clone();
+
+                        let loaded = get_image(&path, app_mode.scale_mode.get(), app_mode.palette.get(), app_mode.hdu_index.get(), app_mode.max_planes.get());
+
+                        let ibox_ref = ibox_mode.lock().unwrap();
+                        let children : Vec<gtk::Widget> = (*ibox_ref).get_children();
+                        for i in 0..children.len() {
+                            (*ibox_ref).remove(&children[i]);
+                        }
+                        let dbox = gtk::Box::new(gtk::Orientation::Vertical, 3);
+                        let dimstr = format!("width/height: {}x{}", loaded.width, loaded.height);
+                        let label = Label::new(Some(&dimstr));
+                        dbox.add(&label);
+                        let dimstr = format!("min/max: {}x{}", loaded.minp, loaded.maxp);
+                        let label2 = Label::new(Some(&dimstr));
+                        dbox.add(&label2);
+                        let hdustr = format!("{} (press 'h' to cycle)",
+                            loaded.hdu_labels.get(app_mode.hdu_index.get()).map(|(_, s)| s.clone()).unwrap_or_default());
+                        let label4 = Label::new(Some(&hdustr));
+                        dbox.add(&label4);
+                        let palettestr = format!("palette: {} (press 'c' to cycle)", app_mode.palette.get().name());
+                        let label5 = Label::new(Some(&palettestr));
+                        dbox.add(&label5);
+                        (*ibox_ref).add(&loaded.image);
+                        (*ibox_ref).add(&dbox);
+                        win.show_all();
+
+                        *app_mode.current_buffer.borrow_mut() = Some((loaded.buffer, loaded.width, loaded.height));
+                        *app_mode.hdu_labels.borrow_mut() = loaded.hdu_labels;
+                        *app_mode.current_final.borrow_mut() = Some(loaded.final_buffer);
+                        if let Some(stack) = loaded.tiff_stack {
+                            spawn_stack_refinement(app_mode.clone(), ibox_mode.clone(), win.clone(), stack, app_mode.stack_generation.get());
+                        }
+                    }
+                }
+                Inhibit(false)
+            });
+
             window.show_all()
 
         });
@@ -387,13 +1183,30 @@ impl Explorer {
 fn main() {
     let args: Vec<_> = env::args().collect();
 
-    let mut image_files : Vec<PathBuf> = vec!();
-    
     if args.len() < 2 {
-        println!("Usage: explorer <path to directory of tiff / fits files>"); 
+        println!("Usage: fex <path to directory of tiff / fits files> [max planes to sum]");
+        println!("       fex --convert <path to directory> [deflate|lzw|packbits]");
         process::exit(1);
     }
 
+    // Headless conversion mode - walk a directory and write a tiff
+    // alongside every fits/tiff file in it, instead of opening the viewer.
+    if args[1] == "--convert" {
+        if args.len() < 3 {
+            println!("Usage: fex --convert <path to directory> [deflate|lzw|packbits]");
+            process::exit(1);
+        }
+        let compressor = match args.get(3).map(|s| s.as_str()) {
+            Some("lzw") => Compressor::Lzw,
+            Some("packbits") => Compressor::PackBits,
+            _ => Compressor::Deflate,
+        };
+        run_convert(Path::new(&args[2]), compressor);
+        return;
+    }
+
+    let mut image_files : Vec<PathBuf> = vec!();
+
     let paths = fs::read_dir(Path::new(&args[1])).unwrap();
 
     for path in paths {
@@ -414,16 +1227,146 @@ fn main() {
             Err(_) => {
                 println!("Error walking directory.");
             }
-            
+
         }
-       
+
     }
     if image_files.len() > 0 {
         image_files.sort_unstable();
+        let max_planes = args.get(2)
+            .and_then(|s| s.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_MAX_PLANES);
         gtk::init().expect("Unable to start GTK3");
-        let app = Explorer::new(image_files);
+        let app = Explorer::new(image_files, max_planes);
         app.run(app.clone());
     } else {
         println!("No image files found in {}.", &args[1]);
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A flat ramp 0..width*height-1 reshaped into height rows of width
+    // columns, so we know exactly which percentile lands on which value.
+    fn ramp_buffer(width : usize, height : usize) -> Vec<Vec<f32>> {
+        let mut buffer = vec![];
+        for y in 0..height {
+            let mut row = vec![];
+            for x in 0..width {
+                row.push((y * width + x) as f32);
+            }
+            buffer.push(row);
+        }
+        buffer
+    }
+
+    #[test]
+    fn percentile_clip_narrows_towards_the_middle() {
+        let buffer = ramp_buffer(100, 100);
+        let (lo, hi) = percentile_clip(&buffer, 100, 100, 0.0, 9999.0, 1.0, 99.0);
+        assert!(lo > 0.0);
+        assert!(hi < 9999.0);
+        assert!(lo < hi);
+    }
+
+    #[test]
+    fn percentile_clip_falls_back_when_min_equals_max() {
+        let buffer = vec![vec![5.0; 4]; 4];
+        let (lo, hi) = percentile_clip(&buffer, 4, 4, 5.0, 5.0, 1.0, 99.0);
+        assert_eq!((lo, hi), (5.0, 5.0));
+    }
+
+    #[test]
+    fn percentile_clip_stays_within_the_requested_range() {
+        let buffer = ramp_buffer(50, 50);
+        let (minp, maxp) = (0.0, 2499.0);
+        let (lo, hi) = percentile_clip(&buffer, 50, 50, minp, maxp, 2.0, 98.0);
+        assert!(lo >= minp);
+        assert!(hi <= maxp);
+    }
+
+    #[test]
+    fn colormap_endpoints_match_the_first_and_last_stop() {
+        let palettes = [Palette::Grayscale, Palette::Viridis, Palette::Inferno, Palette::Heat];
+        for palette in palettes.iter() {
+            let stops = palette.stops();
+            assert_eq!(colormap(0.0, *palette), stops[0]);
+            assert_eq!(colormap(1.0, *palette), stops[stops.len() - 1]);
+        }
+    }
+
+    #[test]
+    fn colormap_clamps_out_of_range_input() {
+        let stops = Palette::Viridis.stops();
+        assert_eq!(colormap(-1.0, Palette::Viridis), stops[0]);
+        assert_eq!(colormap(2.0, Palette::Viridis), stops[stops.len() - 1]);
+    }
+
+    #[test]
+    fn colormap_interpolates_between_stops() {
+        // Grayscale only has two stops, [0,0,0] and [255,255,255], so the
+        // midpoint should land roughly halfway between them.
+        let mid = colormap(0.5, Palette::Grayscale);
+        for channel in mid.iter() {
+            assert!(*channel > 100 && *channel < 156, "expected a mid-grey, got {:?}", mid);
+        }
+    }
+
+    #[test]
+    fn apply_bzero_bscale_passes_through_with_defaults() {
+        // BZERO=0, BSCALE=1 is what a plain floating-point HDU gets -
+        // the raw values should come back untouched.
+        let raw = vec![1.0, -2.5, 3.0, 0.0];
+        let (width, height, buffer) = apply_bzero_bscale([2, 2], &raw, 0.0, 1.0);
+        assert_eq!((width, height), (2, 2));
+        assert_eq!(buffer, vec![vec![1.0, -2.5], vec![3.0, 0.0]]);
+    }
+
+    #[test]
+    fn apply_bzero_bscale_converts_unsigned_integer_storage() {
+        // BZERO=-32768/BSCALE=1 maps raw values held in unsigned 16-bit
+        // storage onto signed physical values: raw 0 should come back
+        // as the physical value -32768, and raw 65535 as 32767.
+        let raw = vec![0.0, 65535.0];
+        let (_, _, buffer) = apply_bzero_bscale([1, 2], &raw, -32768.0, 1.0);
+        assert_eq!(buffer[0], vec![-32768.0, 32767.0]);
+    }
+
+    #[test]
+    fn apply_bzero_bscale_reshapes_row_major() {
+        let raw = vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0];
+        let (width, height, buffer) = apply_bzero_bscale([2, 3], &raw, 0.0, 1.0);
+        assert_eq!((width, height), (3, 2));
+        assert_eq!(buffer, vec![vec![0.0, 1.0, 2.0], vec![3.0, 4.0, 5.0]]);
+    }
+
+    #[test]
+    fn clamp_to_gray16_passes_through_in_range_values() {
+        let buffer = vec![vec![0.0, 1.0, 65535.0]];
+        let data = clamp_to_gray16(3, 1, &buffer);
+        assert_eq!(data, vec![0u16, 1u16, 65535u16]);
+    }
+
+    #[test]
+    fn clamp_to_gray16_clamps_negative_values_to_zero() {
+        let buffer = vec![vec![-1.0, -0.5]];
+        let data = clamp_to_gray16(2, 1, &buffer);
+        assert_eq!(data, vec![0u16, 0u16]);
+    }
+
+    #[test]
+    fn clamp_to_gray16_clamps_overflow_to_u16_max() {
+        let buffer = vec![vec![65536.0, 1000000.0]];
+        let data = clamp_to_gray16(2, 1, &buffer);
+        assert_eq!(data, vec![65535u16, 65535u16]);
+    }
+
+    #[test]
+    fn clamp_to_gray16_flattens_row_major() {
+        let buffer = vec![vec![0.0, 1.0], vec![2.0, 3.0]];
+        let data = clamp_to_gray16(2, 2, &buffer);
+        assert_eq!(data, vec![0u16, 1u16, 2u16, 3u16]);
+    }
+}